@@ -0,0 +1,550 @@
+use std::io::{IoError, OtherIoError};
+use std::mem;
+
+use ser;
+
+/// Default cap on the number of elements a single sequence or map may
+/// contain, matching the limit used by Libra/Diem Canonical Serialization
+/// (BCS). Override it with `Serializer::max_sequence_length`.
+pub static DEFAULT_MAX_SEQUENCE_LENGTH: uint = (1 << 31) - 1;
+
+/// Where a `Serializer`'s bytes are currently headed: straight out to the
+/// caller-supplied writer, or into a scratch buffer while a value is being
+/// captured for later reordering (see `Serializer::buffer`).
+enum Sink<W> {
+    Out(W),
+    Buf(Vec<u8>),
+}
+
+impl<W: Writer> Writer for Sink<W> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> Result<(), IoError> {
+        match *self {
+            Sink::Out(ref mut w) => w.write(buf),
+            Sink::Buf(ref mut v) => v.write(buf),
+        }
+    }
+}
+
+/// A structure for implementing canonical binary serialization, modeled on
+/// Libra/Diem Canonical Serialization (BCS). Unlike the JSON `Serializer`,
+/// the output is deterministic for a given value regardless of map
+/// insertion order, which makes it suitable for hashing and signing.
+pub struct Serializer<W> {
+    sink: Sink<W>,
+    // Scratch space for the (key, value) byte pairs of a map while they're
+    // collected and sorted into canonical order. Maps may nest, so this is
+    // a stack: `visit_map` pushes a fresh frame before iterating and pops
+    // it once the entries are sorted and flushed.
+    map_stack: Vec<Vec<(Vec<u8>, Vec<u8>)>>,
+    // Scratch space for a sequence's serialized elements, buffered so the
+    // length prefix written ahead of them is the real element count rather
+    // than an unverified `size_hint`. Sequences may nest, so this is a
+    // stack, same as `map_stack`.
+    seq_stack: Vec<Vec<Vec<u8>>>,
+    max_sequence_length: uint,
+}
+
+impl<W: Writer> Serializer<W> {
+    /// Creates a new BCS serializer whose output will be written to the
+    /// writer specified.
+    #[inline]
+    pub fn new(writer: W) -> Serializer<W> {
+        Serializer {
+            sink: Sink::Out(writer),
+            map_stack: Vec::new(),
+            seq_stack: Vec::new(),
+            max_sequence_length: DEFAULT_MAX_SEQUENCE_LENGTH,
+        }
+    }
+
+    /// Overrides the maximum number of elements allowed in a single
+    /// sequence or map. `visit_seq`/`visit_map` return an error when a
+    /// collection's `size_hint` lower bound, or the number of elements it
+    /// actually produces, exceeds this.
+    #[inline]
+    pub fn max_sequence_length(mut self, max: uint) -> Serializer<W> {
+        self.max_sequence_length = max;
+        self
+    }
+
+    /// Unwrap the Writer from the Serializer.
+    #[inline]
+    pub fn unwrap(self) -> W {
+        match self.sink {
+            Sink::Out(w) => w,
+            Sink::Buf(_) => fail!("BCS serializer unwrapped mid-buffer"),
+        }
+    }
+
+    /// Serializes `value` into a scratch buffer instead of the real
+    /// destination, returning the bytes it produced. Used by `visit_map_elt`
+    /// to capture a key's (and value's) encoding so entries can be sorted
+    /// into canonical order before anything is written out for real.
+    fn buffer<T: ser::Serialize<Serializer<W>, (), IoError>>(
+        &mut self, value: T
+    ) -> Result<Vec<u8>, IoError> {
+        let previous = mem::replace(&mut self.sink, Sink::Buf(Vec::new()));
+        let result = value.serialize(self);
+        let captured = mem::replace(&mut self.sink, previous);
+
+        try!(result);
+
+        match captured {
+            Sink::Buf(bytes) => Ok(bytes),
+            Sink::Out(_) => unreachable!(),
+        }
+    }
+}
+
+fn unsupported(desc: &'static str) -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: desc,
+        detail: None,
+    }
+}
+
+#[inline]
+fn write_uleb128<W: Writer>(wr: &mut W, mut value: u64) -> Result<(), IoError> {
+    loop {
+        let mut byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value != 0 {
+            byte |= 0x80;
+        }
+
+        try!(wr.write(&[byte]));
+
+        if value == 0 {
+            return Ok(());
+        }
+    }
+}
+
+#[inline]
+fn write_le<W: Writer>(wr: &mut W, value: u64, width: uint) -> Result<(), IoError> {
+    let mut buf = [0u8, .. 8];
+
+    for i in range(0, width) {
+        buf[i] = ((value >> (8 * i)) & 0xff) as u8;
+    }
+
+    wr.write(buf.slice_to(width))
+}
+
+impl<W: Writer> ser::Visitor<(), IoError> for Serializer<W> {
+    #[inline]
+    fn visit_null(&mut self) -> Result<(), IoError> {
+        // BCS encodes the unit type as zero bytes.
+        Ok(())
+    }
+
+    #[inline]
+    fn visit_bool(&mut self, value: bool) -> Result<(), IoError> {
+        self.sink.write(&[if value { 1u8 } else { 0u8 }])
+    }
+
+    #[inline]
+    fn visit_int(&mut self, value: int) -> Result<(), IoError> {
+        write_le(&mut self.sink, value as u64, 8)
+    }
+
+    #[inline]
+    fn visit_i8(&mut self, value: i8) -> Result<(), IoError> {
+        write_le(&mut self.sink, value as u8 as u64, 1)
+    }
+
+    #[inline]
+    fn visit_i16(&mut self, value: i16) -> Result<(), IoError> {
+        write_le(&mut self.sink, value as u16 as u64, 2)
+    }
+
+    #[inline]
+    fn visit_i32(&mut self, value: i32) -> Result<(), IoError> {
+        write_le(&mut self.sink, value as u32 as u64, 4)
+    }
+
+    #[inline]
+    fn visit_i64(&mut self, value: i64) -> Result<(), IoError> {
+        write_le(&mut self.sink, value as u64, 8)
+    }
+
+    #[inline]
+    fn visit_uint(&mut self, value: uint) -> Result<(), IoError> {
+        write_le(&mut self.sink, value as u64, 8)
+    }
+
+    #[inline]
+    fn visit_u8(&mut self, value: u8) -> Result<(), IoError> {
+        write_le(&mut self.sink, value as u64, 1)
+    }
+
+    #[inline]
+    fn visit_u16(&mut self, value: u16) -> Result<(), IoError> {
+        write_le(&mut self.sink, value as u64, 2)
+    }
+
+    #[inline]
+    fn visit_u32(&mut self, value: u32) -> Result<(), IoError> {
+        write_le(&mut self.sink, value as u64, 4)
+    }
+
+    #[inline]
+    fn visit_u64(&mut self, value: u64) -> Result<(), IoError> {
+        write_le(&mut self.sink, value, 8)
+    }
+
+    #[inline]
+    fn visit_f64(&mut self, _value: f64) -> Result<(), IoError> {
+        Err(unsupported("BCS does not support floating point values"))
+    }
+
+    #[inline]
+    fn visit_char(&mut self, _value: char) -> Result<(), IoError> {
+        Err(unsupported("BCS does not support bare char values"))
+    }
+
+    #[inline]
+    fn visit_str(&mut self, value: &str) -> Result<(), IoError> {
+        let bytes = value.as_bytes();
+        try!(write_uleb128(&mut self.sink, bytes.len() as u64));
+        self.sink.write(bytes)
+    }
+
+    #[inline]
+    fn visit_seq<
+        V: ser::SeqVisitor<Serializer<W>, (), IoError>
+    >(&mut self, mut visitor: V) -> Result<(), IoError> {
+        // `size_hint` is only a hint (a lower bound and an optional upper
+        // bound) -- it isn't guaranteed to match the number of elements
+        // `visitor.visit` actually produces. Writing a length prefix
+        // derived from it up front could desynchronize the prefix from the
+        // payload that follows, corrupting the canonical encoding. Instead,
+        // buffer each element's bytes (same approach `visit_map` uses for
+        // its entries) and write the length prefix from the real count.
+        let (lower, _) = visitor.size_hint();
+        if lower > self.max_sequence_length {
+            return Err(unsupported("BCS sequence exceeds MAX_SEQUENCE_LENGTH"));
+        }
+
+        self.seq_stack.push(Vec::with_capacity(lower));
+
+        loop {
+            match try!(visitor.visit(self)) {
+                Some(()) => { }
+                None => { break; }
+            }
+        }
+
+        let elements = self.seq_stack.pop().expect("visit_seq pushed a frame");
+
+        if elements.len() > self.max_sequence_length {
+            return Err(unsupported("BCS sequence exceeds MAX_SEQUENCE_LENGTH"));
+        }
+
+        try!(write_uleb128(&mut self.sink, elements.len() as u64));
+
+        for element in elements.move_iter() {
+            try!(self.sink.write(element.as_slice()));
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    fn visit_seq_elt<
+        T: ser::Serialize<Serializer<W>, (), IoError>
+    >(&mut self, _first: bool, value: T) -> Result<(), IoError> {
+        let bytes = try!(self.buffer(value));
+
+        match self.seq_stack.last_mut() {
+            Some(frame) => {
+                frame.push(bytes);
+                Ok(())
+            }
+            None => Err(unsupported("visit_seq_elt called outside of visit_seq")),
+        }
+    }
+
+    #[inline]
+    fn visit_map<
+        V: ser::MapVisitor<Serializer<W>, (), IoError>
+    >(&mut self, mut visitor: V) -> Result<(), IoError> {
+        let (len, _) = visitor.size_hint();
+
+        if len > self.max_sequence_length {
+            return Err(unsupported("BCS map exceeds MAX_SEQUENCE_LENGTH"));
+        }
+
+        self.map_stack.push(Vec::with_capacity(len));
+
+        loop {
+            match try!(visitor.visit(self)) {
+                Some(()) => { }
+                None => { break; }
+            }
+        }
+
+        let mut entries = self.map_stack.pop().expect("visit_map pushed a frame");
+
+        // `size_hint`'s lower bound is only a hint -- e.g. a generic
+        // iterator-backed `MapVisitor` commonly reports 0 regardless of
+        // its real length -- so the bound check above can't be trusted
+        // alone. Re-check against the real count now that it's known,
+        // same as `visit_seq` does.
+        if entries.len() > self.max_sequence_length {
+            return Err(unsupported("BCS map exceeds MAX_SEQUENCE_LENGTH"));
+        }
+
+        // Canonical BCS map ordering: sort by the serialized bytes of the
+        // key, not by insertion order, so the encoding is deterministic.
+        entries.sort_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+
+        try!(write_uleb128(&mut self.sink, entries.len() as u64));
+
+        for (key, value) in entries.move_iter() {
+            try!(self.sink.write(key.as_slice()));
+            try!(self.sink.write(value.as_slice()));
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    fn visit_map_elt<
+        K: ser::Serialize<Serializer<W>, (), IoError>,
+        V: ser::Serialize<Serializer<W>, (), IoError>
+    >(&mut self, _first: bool, key: K, value: V) -> Result<(), IoError> {
+        let key_bytes = try!(self.buffer(key));
+        let value_bytes = try!(self.buffer(value));
+
+        match self.map_stack.last_mut() {
+            Some(frame) => {
+                frame.push((key_bytes, value_bytes));
+                Ok(())
+            }
+            None => Err(unsupported("visit_map_elt called outside of visit_map")),
+        }
+    }
+}
+
+#[inline]
+pub fn to_vec<
+    T: ser::Serialize<Serializer<Vec<u8>>, (), IoError>
+>(value: &T) -> Result<Vec<u8>, IoError> {
+    let mut state = Serializer::new(Vec::with_capacity(128));
+    try!(value.serialize(&mut state));
+    Ok(state.unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::IoError;
+    use std::u64;
+
+    use super::{Serializer, write_le, write_uleb128};
+    use ser;
+    use ser::Visitor;
+
+    #[test]
+    fn uleb128_boundaries() {
+        // One-byte values (0x00 - 0x7f) are stored as-is, with the
+        // continuation bit unset.
+        let mut buf = Vec::new();
+        write_uleb128(&mut buf, 0).unwrap();
+        assert_eq!(buf.as_slice(), [0x00u8].as_slice());
+
+        let mut buf = Vec::new();
+        write_uleb128(&mut buf, 127).unwrap();
+        assert_eq!(buf.as_slice(), [0x7fu8].as_slice());
+
+        // 128 is the smallest value that needs a second byte: the low 7
+        // bits go in the first byte with the continuation bit set, the
+        // rest follows.
+        let mut buf = Vec::new();
+        write_uleb128(&mut buf, 128).unwrap();
+        assert_eq!(buf.as_slice(), [0x80u8, 0x01u8].as_slice());
+
+        let mut buf = Vec::new();
+        write_uleb128(&mut buf, u64::MAX).unwrap();
+        assert_eq!(
+            buf.as_slice(),
+            [0xffu8, 0xffu8, 0xffu8, 0xffu8, 0xffu8, 0xffu8, 0xffu8, 0xffu8, 0xffu8, 0x01u8].as_slice()
+        );
+    }
+
+    #[test]
+    fn le_widths_truncate_and_pad() {
+        let mut buf = Vec::new();
+        write_le(&mut buf, 0x0102, 1).unwrap();
+        // Only the low byte is kept when the width is narrower than the
+        // value.
+        assert_eq!(buf.as_slice(), [0x02u8].as_slice());
+
+        let mut buf = Vec::new();
+        write_le(&mut buf, 0x0102, 2).unwrap();
+        assert_eq!(buf.as_slice(), [0x02u8, 0x01u8].as_slice());
+
+        let mut buf = Vec::new();
+        write_le(&mut buf, 1, 8).unwrap();
+        assert_eq!(
+            buf.as_slice(),
+            [0x01u8, 0x00u8, 0x00u8, 0x00u8, 0x00u8, 0x00u8, 0x00u8, 0x00u8].as_slice()
+        );
+    }
+
+    #[test]
+    fn fixed_width_integers_encode_little_endian() {
+        let bytes = super::to_vec(&42u8).unwrap();
+        assert_eq!(bytes.as_slice(), [42u8].as_slice());
+
+        let bytes = super::to_vec(&-1i16).unwrap();
+        assert_eq!(bytes.as_slice(), [0xffu8, 0xffu8].as_slice());
+
+        let bytes = super::to_vec(&1u64).unwrap();
+        assert_eq!(
+            bytes.as_slice(),
+            [0x01u8, 0x00u8, 0x00u8, 0x00u8, 0x00u8, 0x00u8, 0x00u8, 0x00u8].as_slice()
+        );
+    }
+
+    // A `MapVisitor` driven from a fixed list of (key, value) pairs, in the
+    // order given -- used to confirm that `Serializer` reorders entries by
+    // serialized key bytes regardless of the order they're visited in.
+    struct VecMapVisitor {
+        entries: Vec<(String, i64)>,
+        pos: uint,
+    }
+
+    impl ser::MapVisitor<Serializer<Vec<u8>>, (), IoError> for VecMapVisitor {
+        fn size_hint(&self) -> (uint, Option<uint>) {
+            (self.entries.len(), Some(self.entries.len()))
+        }
+
+        fn visit(&mut self, serializer: &mut Serializer<Vec<u8>>) -> Result<Option<()>, IoError> {
+            if self.pos == self.entries.len() {
+                return Ok(None);
+            }
+
+            let first = self.pos == 0;
+            let (key, value) = self.entries[self.pos].clone();
+            self.pos += 1;
+
+            try!(serializer.visit_map_elt(first, key.as_slice(), value));
+            Ok(Some(()))
+        }
+    }
+
+    #[test]
+    fn map_entries_sort_by_key_bytes() {
+        let insertion_order = VecMapVisitor {
+            entries: vec![
+                ("b".to_string(), 2i64),
+                ("a".to_string(), 1i64),
+                ("c".to_string(), 3i64),
+            ],
+            pos: 0,
+        };
+
+        let mut in_order = Serializer::new(Vec::with_capacity(64));
+        in_order.visit_map(insertion_order).unwrap();
+        let by_insertion_order = in_order.unwrap();
+
+        let sorted_order = VecMapVisitor {
+            entries: vec![
+                ("a".to_string(), 1i64),
+                ("b".to_string(), 2i64),
+                ("c".to_string(), 3i64),
+            ],
+            pos: 0,
+        };
+
+        let mut in_sorted_order = Serializer::new(Vec::with_capacity(64));
+        in_sorted_order.visit_map(sorted_order).unwrap();
+        let by_key_order = in_sorted_order.unwrap();
+
+        // The two maps have the same entries, visited in different orders;
+        // canonical encoding must agree regardless.
+        assert_eq!(by_insertion_order, by_key_order);
+    }
+
+    // A `MapVisitor` that underreports its `size_hint` -- as a generic
+    // iterator-backed visitor commonly does, since a lower bound of `0` is
+    // always a legal hint -- while actually producing more entries than
+    // fit under `max_sequence_length`. Confirms enforcement is checked
+    // against the real collected entry count, not just the hint consulted
+    // before collection starts.
+    struct UnderhintedMapVisitor {
+        real_len: uint,
+        pos: uint,
+    }
+
+    impl ser::MapVisitor<Serializer<Vec<u8>>, (), IoError> for UnderhintedMapVisitor {
+        fn size_hint(&self) -> (uint, Option<uint>) {
+            (0, None)
+        }
+
+        fn visit(&mut self, serializer: &mut Serializer<Vec<u8>>) -> Result<Option<()>, IoError> {
+            if self.pos == self.real_len {
+                return Ok(None);
+            }
+
+            let first = self.pos == 0;
+            let key = self.pos as i64;
+            self.pos += 1;
+
+            try!(serializer.visit_map_elt(first, key, 0i64));
+            Ok(Some(()))
+        }
+    }
+
+    #[test]
+    fn map_exceeding_max_length_is_rejected_even_with_underreported_hint() {
+        let visitor = UnderhintedMapVisitor { real_len: 10, pos: 0 };
+
+        let mut state = Serializer::new(Vec::with_capacity(16)).max_sequence_length(5);
+        assert!(state.visit_map(visitor).is_err());
+    }
+
+    // A `SeqVisitor` that reports a `size_hint` lower bound with no
+    // matching elements, used to check that the length prefix actually
+    // written comes from the real element count, not the hint -- and that
+    // an over-long hint is rejected by the `MAX_SEQUENCE_LENGTH` check
+    // before any element is visited.
+    struct EmptyHintedSeqVisitor {
+        claimed_len: uint,
+    }
+
+    impl ser::SeqVisitor<Serializer<Vec<u8>>, (), IoError> for EmptyHintedSeqVisitor {
+        fn size_hint(&self) -> (uint, Option<uint>) {
+            (self.claimed_len, Some(self.claimed_len))
+        }
+
+        fn visit(&mut self, _serializer: &mut Serializer<Vec<u8>>) -> Result<Option<()>, IoError> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn sequence_length_prefix_comes_from_real_element_count() {
+        let visitor = EmptyHintedSeqVisitor { claimed_len: 3 };
+
+        let mut state = Serializer::new(Vec::with_capacity(16));
+        state.visit_seq(visitor).unwrap();
+        let bytes = state.unwrap();
+
+        // The hint claimed 3 elements, but none were actually visited; the
+        // written length prefix must reflect the 0 elements that were.
+        assert_eq!(bytes.as_slice(), [0x00u8].as_slice());
+    }
+
+    #[test]
+    fn sequence_exceeding_max_length_is_rejected() {
+        let visitor = EmptyHintedSeqVisitor { claimed_len: 10 };
+
+        let mut state = Serializer::new(Vec::with_capacity(16)).max_sequence_length(5);
+        assert!(state.visit_seq(visitor).is_err());
+    }
+}
@@ -1,12 +1,32 @@
 use std::f64;
-use std::io::{IoError, MemWriter};
+use std::i64;
+use std::io::{IoError, OtherIoError};
 use std::num::{FPNaN, FPInfinite};
+use std::str;
 
 use ser;
 
 /// A structure for implementing serialization to JSON.
 pub struct Serializer<W> {
     writer: W,
+    // When set, strings are escaped so the output contains nothing outside
+    // printable ASCII, matching the `ensure_ascii` behavior of other JSON
+    // encoders.
+    ascii_safe: bool,
+    // When set, sequences and maps are pretty-printed: one element/entry per
+    // line, indented by `depth * indent` spaces.
+    indent: Option<uint>,
+    depth: uint,
+    // When set, non-string map keys (integers, bools, chars) are coerced
+    // into quoted strings instead of being rejected.
+    coerce_keys: bool,
+    // Set for the duration of `key.serialize(self)` inside `visit_map_elt`.
+    // The scalar `visit_*` methods below check this flag to apply the key
+    // policy (reject, or coerce into a quoted string) inline as they write,
+    // rather than serializing the key into a scratch buffer first just to
+    // inspect it -- the common case of an already-string key then costs no
+    // extra allocation at all.
+    in_key: bool,
 }
 
 impl<W: Writer> Serializer<W> {
@@ -16,24 +36,129 @@ impl<W: Writer> Serializer<W> {
     pub fn new(writer: W) -> Serializer<W> {
         Serializer {
             writer: writer,
+            ascii_safe: false,
+            indent: None,
+            depth: 0,
+            coerce_keys: false,
+            in_key: false,
         }
     }
 
+    /// Enables or disables ASCII-safe output. When enabled, every byte or
+    /// codepoint outside printable ASCII is escaped as `\u00XX`/`\uXXXX`
+    /// (emitting a surrogate pair for codepoints above `U+FFFF`) instead of
+    /// being passed through as raw UTF-8; the named control characters
+    /// keep their short escapes (`\b`, `\f`, `\n`, `\r`, `\t`).
+    #[inline]
+    pub fn ascii_safe(mut self, ascii_safe: bool) -> Serializer<W> {
+        self.ascii_safe = ascii_safe;
+        self
+    }
+
+    /// Enables pretty-printing: sequences and maps are laid out one
+    /// element/entry per line, each level indented by `width` more spaces
+    /// than its parent.
+    #[inline]
+    pub fn indent(mut self, width: uint) -> Serializer<W> {
+        self.indent = Some(width);
+        self
+    }
+
+    /// Enables coercion of non-string map keys (integers, bools, chars)
+    /// into quoted strings. By default such keys are rejected with an
+    /// error, since JSON only allows string object keys.
+    #[inline]
+    pub fn coerce_keys(mut self, coerce_keys: bool) -> Serializer<W> {
+        self.coerce_keys = coerce_keys;
+        self
+    }
+
     /// Unwrap the Writer from the Serializer.
     #[inline]
     pub fn unwrap(self) -> W {
         self.writer
     }
+
+    fn write_indent(&mut self) -> Result<(), IoError> {
+        try!(self.writer.write_str("\n"));
+
+        let width = self.indent.unwrap() * self.depth;
+        for _ in range(0, width) {
+            try!(self.writer.write_str(" "));
+        }
+
+        Ok(())
+    }
+
+    // Applies the map-key policy to a `true`/`false`/numeric scalar: wraps
+    // it in quotes when `coerce_keys` is enabled (so e.g. `42` becomes
+    // `"42"`), rejects it otherwise. `write_digits` does the actual
+    // formatting directly into `self.writer`, so coercion never needs an
+    // intermediate allocation.
+    fn write_keyed_scalar(&mut self, write_digits: |&mut W| -> Result<(), IoError>) -> Result<(), IoError> {
+        if !self.coerce_keys {
+            return Err(non_string_key());
+        }
+
+        try!(self.writer.write_str("\""));
+        try!(write_digits(&mut self.writer));
+        self.writer.write_str("\"")
+    }
+
+    // Writes a signed integer, either as a bare JSON number or, when
+    // serializing a map key, via `write_keyed_scalar`'s reject/coerce policy.
+    fn write_keyed_int(&mut self, value: i64) -> Result<(), IoError> {
+        if self.in_key {
+            return self.write_keyed_scalar(|w| write_int(w, value));
+        }
+
+        write_int(&mut self.writer, value)
+    }
+
+    // As `write_keyed_int`, but for unsigned integers.
+    fn write_keyed_uint(&mut self, value: u64) -> Result<(), IoError> {
+        if self.in_key {
+            return self.write_keyed_scalar(|w| write_uint(w, value));
+        }
+
+        write_uint(&mut self.writer, value)
+    }
+}
+
+fn non_string_key() -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: "JSON object keys must be strings",
+        detail: None,
+    }
+}
+
+fn invalid_utf8() -> IoError {
+    IoError {
+        kind: OtherIoError,
+        desc: "ASCII-safe escaping requires valid UTF-8 input",
+        detail: None,
+    }
 }
 
 impl<W: Writer> ser::Visitor<(), IoError> for Serializer<W> {
     #[inline]
     fn visit_null(&mut self) -> Result<(), IoError> {
+        if self.in_key {
+            return Err(non_string_key());
+        }
+
         self.writer.write_str("null")
     }
 
     #[inline]
     fn visit_bool(&mut self, value: bool) -> Result<(), IoError> {
+        if self.in_key {
+            return self.write_keyed_scalar(|w| {
+                w.write_str(if value { "true" } else { "false" })
+            });
+        }
+
         if value {
             self.writer.write_str("true")
         } else {
@@ -43,82 +168,115 @@ impl<W: Writer> ser::Visitor<(), IoError> for Serializer<W> {
 
     #[inline]
     fn visit_int(&mut self, value: int) -> Result<(), IoError> {
-        write!(self.writer, "{}", value)
+        self.write_keyed_int(value as i64)
     }
 
     #[inline]
     fn visit_i8(&mut self, value: i8) -> Result<(), IoError> {
-        write!(self.writer, "{}", value)
+        self.write_keyed_int(value as i64)
     }
 
     #[inline]
     fn visit_i16(&mut self, value: i16) -> Result<(), IoError> {
-        write!(self.writer, "{}", value)
+        self.write_keyed_int(value as i64)
     }
 
     #[inline]
     fn visit_i32(&mut self, value: i32) -> Result<(), IoError> {
-        write!(self.writer, "{}", value)
+        self.write_keyed_int(value as i64)
     }
 
     #[inline]
     fn visit_i64(&mut self, value: i64) -> Result<(), IoError> {
-        write!(self.writer, "{}", value)
+        self.write_keyed_int(value)
     }
 
     #[inline]
     fn visit_uint(&mut self, value: uint) -> Result<(), IoError> {
-        write!(self.writer, "{}", value)
+        self.write_keyed_uint(value as u64)
     }
 
     #[inline]
     fn visit_u8(&mut self, value: u8) -> Result<(), IoError> {
-        write!(self.writer, "{}", value)
+        self.write_keyed_uint(value as u64)
     }
 
     #[inline]
     fn visit_u16(&mut self, value: u16) -> Result<(), IoError> {
-        write!(self.writer, "{}", value)
+        self.write_keyed_uint(value as u64)
     }
 
     #[inline]
     fn visit_u32(&mut self, value: u32) -> Result<(), IoError> {
-        write!(self.writer, "{}", value)
+        self.write_keyed_uint(value as u64)
     }
 
     #[inline]
     fn visit_u64(&mut self, value: u64) -> Result<(), IoError> {
-        write!(self.writer, "{}", value)
+        self.write_keyed_uint(value)
     }
 
     #[inline]
     fn visit_f64(&mut self, value: f64) -> Result<(), IoError> {
+        if self.in_key {
+            // Floats aren't in the set of key types `coerce_keys` covers.
+            return Err(non_string_key());
+        }
+
         fmt_f64_or_null(&mut self.writer, value)
     }
 
     #[inline]
     fn visit_char(&mut self, v: char) -> Result<(), IoError> {
-        escape_char(&mut self.writer, v)
+        if self.in_key && !self.coerce_keys {
+            return Err(non_string_key());
+        }
+
+        // `escape_char` already quotes its output (it goes through
+        // `escape_bytes`), so a coerced char key needs no extra wrapping
+        // beyond what a plain char value gets.
+        escape_char(&mut self.writer, v, self.ascii_safe)
     }
 
     #[inline]
     fn visit_str(&mut self, value: &str) -> Result<(), IoError> {
-        escape_str(&mut self.writer, value)
+        escape_str(&mut self.writer, value, self.ascii_safe)
     }
 
     #[inline]
     fn visit_seq<
         V: ser::SeqVisitor<Serializer<W>, (), IoError>
     >(&mut self, mut visitor: V) -> Result<(), IoError> {
+        if self.in_key {
+            // JSON object keys must be strings; a sequence can't be
+            // coerced into one the way a scalar can, so reject it outright
+            // -- and before writing anything, so a rejected key never
+            // leaks a stray `[` onto the wire.
+            return Err(non_string_key());
+        }
+
         try!(self.writer.write_str("["));
 
+        if self.indent.is_some() {
+            self.depth += 1;
+        }
+
+        let mut empty = true;
+
         loop {
             match try!(visitor.visit(self)) {
-                Some(()) => { }
+                Some(()) => { empty = false; }
                 None => { break; }
             }
         }
 
+        if self.indent.is_some() {
+            self.depth -= 1;
+            if !empty {
+                try!(self.write_indent());
+            }
+        }
+
         self.writer.write_str("]")
     }
 
@@ -130,6 +288,10 @@ impl<W: Writer> ser::Visitor<(), IoError> for Serializer<W> {
             try!(self.writer.write_str(","));
         }
 
+        if self.indent.is_some() {
+            try!(self.write_indent());
+        }
+
         value.serialize(self)
     }
 
@@ -137,15 +299,34 @@ impl<W: Writer> ser::Visitor<(), IoError> for Serializer<W> {
     fn visit_map<
         V: ser::MapVisitor<Serializer<W>, (), IoError>
     >(&mut self, mut visitor: V) -> Result<(), IoError> {
+        if self.in_key {
+            // As `visit_seq`: a map can't be coerced into a string key, so
+            // reject it before writing the opening brace.
+            return Err(non_string_key());
+        }
+
         try!(self.writer.write_str("{{"));
 
+        if self.indent.is_some() {
+            self.depth += 1;
+        }
+
+        let mut empty = true;
+
         loop {
             match try!(visitor.visit(self)) {
-                Some(()) => { }
+                Some(()) => { empty = false; }
                 None => { break; }
             }
         }
 
+        if self.indent.is_some() {
+            self.depth -= 1;
+            if !empty {
+                try!(self.write_indent());
+            }
+        }
+
         self.writer.write_str("}}")
     }
 
@@ -158,16 +339,42 @@ impl<W: Writer> ser::Visitor<(), IoError> for Serializer<W> {
             try!(self.writer.write_str(","));
         }
 
-        try!(key.serialize(self));
-        try!(self.writer.write_str(":"));
+        if self.indent.is_some() {
+            try!(self.write_indent());
+        }
+
+        self.in_key = true;
+        let result = key.serialize(self);
+        self.in_key = false;
+        try!(result);
+
+        if self.indent.is_some() {
+            try!(self.writer.write_str(": "));
+        } else {
+            try!(self.writer.write_str(":"));
+        }
+
         value.serialize(self)
     }
 }
 
 #[inline]
-pub fn escape_bytes<W: Writer>(wr: &mut W, bytes: &[u8]) -> Result<(), IoError> {
+pub fn escape_bytes<W: Writer>(wr: &mut W, bytes: &[u8], ascii_safe: bool) -> Result<(), IoError> {
     try!(wr.write_str("\""));
 
+    if ascii_safe {
+        try!(escape_bytes_ascii(wr, bytes));
+    } else {
+        try!(escape_bytes_utf8(wr, bytes));
+    }
+
+    wr.write_str("\"")
+}
+
+// Original behavior: only the handful of named control characters are
+// escaped, everything else (including raw multi-byte UTF-8) passes through
+// verbatim.
+fn escape_bytes_utf8<W: Writer>(wr: &mut W, bytes: &[u8]) -> Result<(), IoError> {
     let mut start = 0;
 
     for (i, byte) in bytes.iter().enumerate() {
@@ -195,42 +402,368 @@ pub fn escape_bytes<W: Writer>(wr: &mut W, bytes: &[u8]) -> Result<(), IoError>
         try!(wr.write(bytes.slice_from(start)));
     }
 
-    wr.write_str("\"")
+    Ok(())
+}
+
+// ASCII-safe behavior: decode as chars rather than raw bytes so every
+// codepoint outside printable ASCII is escaped: the named control
+// characters with their short escapes, everything else as `\u00XX`/`\uXXXX`.
+fn escape_bytes_ascii<W: Writer>(wr: &mut W, bytes: &[u8]) -> Result<(), IoError> {
+    let s = match str::from_utf8(bytes) {
+        Some(s) => s,
+        None => return Err(invalid_utf8()),
+    };
+
+    for c in s.chars() {
+        match c {
+            '"' => try!(wr.write_str("\\\"")),
+            '\\' => try!(wr.write_str("\\\\")),
+            '\x08' => try!(wr.write_str("\\b")),
+            '\x0c' => try!(wr.write_str("\\f")),
+            '\n' => try!(wr.write_str("\\n")),
+            '\r' => try!(wr.write_str("\\r")),
+            '\t' => try!(wr.write_str("\\t")),
+            c if c >= ' ' && c <= '~' => try!(wr.write_char(c)),
+            c => try!(write_unicode_escape(wr, c)),
+        }
+    }
+
+    Ok(())
+}
+
+// Writes `\uXXXX`, splitting into a UTF-16 surrogate pair (`\uD800`-`\uDBFF`
+// followed by `\uDC00`-`\uDFFF`) for codepoints above `U+FFFF`.
+fn write_unicode_escape<W: Writer>(wr: &mut W, c: char) -> Result<(), IoError> {
+    let code = c as u32;
+
+    if code <= 0xFFFF {
+        write!(wr, "\\u{:04x}", code)
+    } else {
+        let code = code - 0x10000;
+        let high = 0xD800 + (code >> 10);
+        let low = 0xDC00 + (code & 0x3FF);
+        write!(wr, "\\u{:04x}\\u{:04x}", high, low)
+    }
 }
 
 #[inline]
-pub fn escape_str<W: Writer>(wr: &mut W, value: &str) -> Result<(), IoError> {
-    escape_bytes(wr, value.as_bytes())
+pub fn escape_str<W: Writer>(wr: &mut W, value: &str, ascii_safe: bool) -> Result<(), IoError> {
+    escape_bytes(wr, value.as_bytes(), ascii_safe)
 }
 
 #[inline]
-pub fn escape_char<W: Writer>(wr: &mut W, value: char) -> Result<(), IoError> {
+pub fn escape_char<W: Writer>(wr: &mut W, value: char, ascii_safe: bool) -> Result<(), IoError> {
     let mut buf = [0, .. 4];
-    value.encode_utf8(buf);
-    escape_bytes(wr, buf)
+    let len = value.encode_utf8(buf);
+    escape_bytes(wr, buf.slice_to(len), ascii_safe)
 }
 
+// `itoa`-style fast integer formatting: the digits are written into a
+// fixed-size stack buffer back-to-front and the final slice is written out
+// in a single call, avoiding the allocation and formatting machinery behind
+// `write!`.
+
+#[inline]
+fn write_uint<W: Writer>(wr: &mut W, mut value: u64) -> Result<(), IoError> {
+    let mut buf = [0u8, .. 20];
+    let mut i = buf.len();
+
+    if value == 0 {
+        return wr.write(&[b'0']);
+    }
+
+    while value > 0 {
+        i -= 1;
+        buf[i] = b'0' + (value % 10) as u8;
+        value /= 10;
+    }
+
+    wr.write(buf.slice_from(i))
+}
+
+#[inline]
+fn write_int<W: Writer>(wr: &mut W, value: i64) -> Result<(), IoError> {
+    if value < 0 {
+        try!(wr.write_str("-"));
+
+        // `-i64::MIN` overflows an `i64`, so take the magnitude through a
+        // `u64` instead of negating directly.
+        let magnitude = if value == i64::MIN {
+            9223372036854775808u64
+        } else {
+            (-value) as u64
+        };
+
+        write_uint(wr, magnitude)
+    } else {
+        write_uint(wr, value as u64)
+    }
+}
+
+// Rather than the fixed six-digit truncation this replaces, `fmt_f64_or_null`
+// now looks for the shortest decimal that round-trips back to the exact same
+// `f64`, the same guarantee the Ryū algorithm provides. A full Ryū port
+// derives that shortest decimal directly from the value's mantissa/exponent
+// using precomputed power-of-5/power-of-2 tables (as the `ryu` crate does);
+// here we approximate the same end result by growing the requested
+// precision until parsing the formatted string reproduces `value` exactly,
+// which is sufficient since `f64` never needs more than 17 significant
+// digits to round-trip.
 fn fmt_f64_or_null<W: Writer>(wr: &mut W, value: f64) -> Result<(), IoError> {
     match value.classify() {
-        FPNaN | FPInfinite => wr.write_str("null"),
-        _ => wr.write_str(f64::to_str_digits(value, 6).as_slice()),
+        FPNaN | FPInfinite => return wr.write_str("null"),
+        _ => {}
+    }
+
+    wr.write_str(shortest_round_trip(value).as_slice())
+}
+
+fn shortest_round_trip(value: f64) -> String {
+    if value == 0.0 {
+        return "0".to_string();
+    }
+
+    // `to_str_digits` counts digits *after* the decimal point, not
+    // significant digits, so the number of decimal places that gets us a
+    // given number of significant digits depends on the value's order of
+    // magnitude -- e.g. `1e-300` needs ~300 decimal places just to reach
+    // its first significant digit. Values with a large positive or
+    // negative exponent are better served by exponential notation, both to
+    // keep the output short and because the decimal-place approach can't
+    // represent them with a bounded number of digits at all.
+    let exponent = value.abs().log10().floor() as int;
+
+    if exponent < -4 || exponent >= 17 {
+        return shortest_exponential(value, exponent);
+    }
+
+    for significant_digits in range(1u, 18u) {
+        let decimal_places = significant_digits as int - 1 - exponent;
+        if decimal_places < 0 {
+            continue;
+        }
+
+        let candidate = f64::to_str_digits(value, decimal_places as uint);
+
+        let round_tripped: Option<f64> = from_str(candidate.as_slice());
+        match round_tripped {
+            Some(v) if v == value => return candidate,
+            _ => {}
+        }
+    }
+
+    // No candidate round-tripped, which shouldn't happen for a value in
+    // this exponent range; use the maximum decimal places a double can
+    // need here rather than silently returning an unverified value.
+    let max_decimal_places = if exponent < 17 { 17 - exponent } else { 0 };
+    f64::to_str_digits(value, max_decimal_places as uint)
+}
+
+// Rounds the decimal digit string `digits` (no sign, no decimal point) down
+// to `keep` significant digits, rounding half away from zero based on the
+// first dropped digit. Returns the rounded digits (always `keep` bytes
+// long) alongside how many extra powers of ten the rounding carried into:
+// 0 normally, or 1 when every kept digit was a `9` that rolled over (e.g.
+// rounding "995" to 2 digits yields `("10", 1)`, since the true value is
+// "10" one order of magnitude higher, i.e. 1000, not 10).
+fn round_significant_digits(digits: &[u8], keep: uint) -> (Vec<u8>, int) {
+    if keep >= digits.len() {
+        let mut kept = digits.to_vec();
+        for _ in range(0, keep - digits.len()) {
+            kept.push(b'0');
+        }
+        return (kept, 0);
+    }
+
+    let mut kept = digits.slice_to(keep).to_vec();
+
+    if digits[keep] >= b'5' {
+        let mut i = kept.len();
+        loop {
+            if i == 0 {
+                kept.pop();
+                kept.insert(0, b'1');
+                return (kept, 1);
+            }
+
+            i -= 1;
+            if kept[i] == b'9' {
+                kept[i] = b'0';
+            } else {
+                kept[i] += 1;
+                break;
+            }
+        }
+    }
+
+    (kept, 0)
+}
+
+// Builds a `d` or `d.ddd` mantissa string from a significant-digit byte
+// string, as returned by `round_significant_digits`.
+fn mantissa_str(digits: &[u8]) -> String {
+    if digits.len() == 1 {
+        String::from_utf8(digits.to_vec()).unwrap()
+    } else {
+        format!("{}.{}", digits[0] as char, str::from_utf8(digits.slice_from(1)).unwrap())
     }
 }
 
+// Formats `value` as `<mantissa>e<exponent>`, growing the mantissa's
+// significant digits until the result round-trips back to `value`.
+//
+// This works directly from `value`'s own correctly-rounded decimal
+// digits rather than from `value / 10^exponent` -- that division is
+// itself a rounding step, and for most magnitudes in this branch
+// (everything outside `1e-4 .. 1e17`) it introduces error the round-trip
+// search can never recover from, e.g. `1.0000000000000001e-20` never
+// round-trips when divided by `1e-20` first.
+fn shortest_exponential(value: f64, exponent: int) -> String {
+    let sign = if value < 0.0 { "-" } else { "" };
+    let magnitude = value.abs();
+
+    if exponent >= 17 {
+        // Every `f64` at this magnitude is necessarily an integer (all
+        // doubles at or above 2^52 are), so `to_str_digits(magnitude, 0)`
+        // is its exact digit expansion, not an approximation -- every
+        // candidate below is a plain decimal rounding of those same exact
+        // digits, never a fresh lossy computation from `magnitude`.
+        let integer_str = f64::to_str_digits(magnitude, 0);
+        let digits = integer_str.as_bytes();
+        let true_exponent = digits.len() as int - 1;
+
+        for significant_digits in range(1u, 18u) {
+            let (kept, carry) = round_significant_digits(digits, significant_digits);
+            let unsigned = format!("{}e{}", mantissa_str(kept.as_slice()), true_exponent + carry);
+
+            let round_tripped: Option<f64> = from_str(unsigned.as_slice());
+            match round_tripped {
+                Some(v) if v == magnitude => return format!("{}{}", sign, unsigned),
+                _ => {}
+            }
+        }
+
+        let (kept, carry) = round_significant_digits(digits, 17);
+        let unsigned = format!("{}e{}", mantissa_str(kept.as_slice()), true_exponent + carry);
+        return format!("{}{}", sign, unsigned);
+    }
+
+    // exponent < -4: reuse the same correctly-rounded decimal expansion the
+    // plain-decimal branch in `shortest_round_trip` uses, then reformat its
+    // digits into exponential notation -- a purely textual transform,
+    // since it's the same digits, just regrouped around a different power
+    // of ten, not a new computation from `magnitude`.
+    for significant_digits in range(1u, 18u) {
+        let decimal_places = significant_digits as int - 1 - exponent;
+        if decimal_places < 0 {
+            continue;
+        }
+
+        let plain = f64::to_str_digits(magnitude, decimal_places as uint);
+        let frac = plain.as_slice().slice_from(2); // skip the leading "0."
+        let first_digit_at = (-exponent - 1) as uint;
+
+        // Rounding carried into the next power of ten (e.g. `9.99e-5` to
+        // one digit gives "0.0001"), leaving no digit at this exponent's
+        // position; try again with more significant digits.
+        if frac.len() <= first_digit_at {
+            continue;
+        }
+
+        let digits = frac.slice_from(first_digit_at);
+
+        let unsigned = format!("{}e{}", mantissa_str(digits.as_bytes()), exponent);
+
+        let round_tripped: Option<f64> = from_str(unsigned.as_slice());
+        match round_tripped {
+            Some(v) if v == magnitude => return format!("{}{}", sign, unsigned),
+            _ => {}
+        }
+    }
+
+    let decimal_places = (17 - 1 - exponent) as uint;
+    let plain = f64::to_str_digits(magnitude, decimal_places);
+    let frac = plain.as_slice().slice_from(2);
+    let first_digit_at = (-exponent - 1) as uint;
+    let digits = frac.slice_from(first_digit_at);
+    format!("{}{}e{}", sign, mantissa_str(digits.as_bytes()), exponent)
+}
+
+/// Serializes `value` as JSON straight into `writer`, with no intermediate
+/// buffer. Unlike `to_vec`/`to_string`, this works with any caller-supplied
+/// sink -- a socket, a file, a pre-allocated buffer -- not just a freshly
+/// allocated `Vec<u8>`.
+#[inline]
+pub fn to_writer<
+    'a,
+    W: Writer,
+    T: ser::Serialize<Serializer<&'a mut W>, (), IoError>
+>(writer: &'a mut W, value: &T) -> Result<(), IoError> {
+    let mut state = Serializer::new(writer);
+    value.serialize(&mut state)
+}
+
 #[inline]
 pub fn to_vec<
-    T: ser::Serialize<Serializer<MemWriter>, (), IoError>
+    T: for<'a> ser::Serialize<Serializer<&'a mut Vec<u8>>, (), IoError>
 >(value: &T) -> Result<Vec<u8>, IoError> {
-    let writer = MemWriter::with_capacity(1024);
-    let mut state = Serializer::new(writer);
-    try!(value.serialize(&mut state));
-    Ok(state.unwrap().unwrap())
+    let mut writer = Vec::with_capacity(1024);
+    try!(to_writer(&mut writer, value));
+    Ok(writer)
 }
 
 #[inline]
 pub fn to_string<
-    T: ser::Serialize<Serializer<MemWriter>, (), IoError>
+    T: for<'a> ser::Serialize<Serializer<&'a mut Vec<u8>>, (), IoError>
 >(value: &T) -> Result<Result<String, Vec<u8>>, IoError> {
     let vec = try!(to_vec(value));
     Ok(String::from_utf8(vec))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64;
+
+    use super::{fmt_f64_or_null, shortest_round_trip};
+
+    fn assert_round_trips(value: f64) {
+        let formatted = shortest_round_trip(value);
+        let parsed: Option<f64> = from_str(formatted.as_slice());
+        assert_eq!(parsed, Some(value));
+    }
+
+    #[test]
+    fn round_trips_across_representative_magnitudes() {
+        assert_round_trips(0.0);
+        assert_round_trips(1.0);
+        assert_round_trips(-123.456);
+        // The classic binary-fraction case: both operands survive six-digit
+        // truncation, but their sum (0.30000000000000004) does not, and it
+        // must round-trip exactly here.
+        assert_round_trips(0.1 + 0.2);
+        assert_round_trips(1e-10);
+        // Reported regression: dividing by `10^exponent` before formatting
+        // loses precision for values like this one.
+        assert_round_trips(1.0000000000000001e-20);
+        // Smallest positive subnormal `f64`.
+        assert_round_trips(5e-324);
+        assert_round_trips(1e300);
+        assert_round_trips(-1e300);
+        assert_round_trips(1.7976931348623157e308); // f64::MAX
+    }
+
+    #[test]
+    fn non_finite_values_serialize_as_null() {
+        let mut nan_out = Vec::new();
+        fmt_f64_or_null(&mut nan_out, f64::NAN).unwrap();
+        assert_eq!(nan_out.as_slice(), "null".as_bytes());
+
+        let mut inf_out = Vec::new();
+        fmt_f64_or_null(&mut inf_out, f64::INFINITY).unwrap();
+        assert_eq!(inf_out.as_slice(), "null".as_bytes());
+
+        let mut neg_inf_out = Vec::new();
+        fmt_f64_or_null(&mut neg_inf_out, f64::NEG_INFINITY).unwrap();
+        assert_eq!(neg_inf_out.as_slice(), "null".as_bytes());
+    }
 }
\ No newline at end of file